@@ -1,40 +1,26 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
 use sha3::{Digest, Sha3_256};
 
 
-#[derive(Clone)]
-struct Node {
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
-    hash: Vec<u8>,
+struct MerkleTree<D: Digest> {
+    nodes: Vec<Vec<u8>>,
+    level_offsets: Vec<usize>,
+    size: usize,
+    _digest: PhantomData<D>,
 }
 
+type Sha3MerkleTree = MerkleTree<Sha3_256>;
 
-impl Node {
-    fn new_leaf(hash: Vec<u8>) -> Self {
-        Self {
-            left: None,
-            right: None,
-            hash: hash,
-        }
-    }
 
-    fn new_internal(hash: Vec<u8>, left: Box<Node>, right: Box<Node>) -> Self {
-        Self {
-            left: Some(left),
-            right: Some(right),
-            hash: hash,
-        }
-    }
-}
-
-struct MerkleTree {
-    root: Node,
-    size: usize,
-}
+const LEAF_TWEAK: u8 = 0x00;
+const NODE_TWEAK: u8 = 0x01;
 
 
-fn _sha3(input: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha3_256::default();
+fn _sha3<D: Digest>(input: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.input(&[LEAF_TWEAK]);
     hasher.input(input);
 
     let mut res = Vec::new();
@@ -45,8 +31,9 @@ fn _sha3(input: &[u8]) -> Vec<u8> {
     res
 }
 
-fn _sha3_leaves(leaf_hash_1: &[u8], leaf_hash_2: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha3_256::default();
+fn _sha3_leaves<D: Digest>(leaf_hash_1: &[u8], leaf_hash_2: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.input(&[NODE_TWEAK]);
     hasher.input(leaf_hash_1);
     hasher.input(leaf_hash_2);
 
@@ -59,95 +46,381 @@ fn _sha3_leaves(leaf_hash_1: &[u8], leaf_hash_2: &[u8]) -> Vec<u8> {
 }
 
 
-impl MerkleTree {
+impl<D: Digest> MerkleTree<D> {
     fn build(items: Vec<&[u8]>) -> Self {
-        let leaves: Vec<Box<Node>> = items
-            .into_iter()
-            .map(|leaf_data| _sha3(leaf_data))
-            .map(|hash| Box::new(Node::new_leaf(hash)))
-            .collect();
+        let size = items.len();
 
-        let size = leaves.len();
-        let root = Self::_build_up(leaves)[0].clone();
+        let mut nodes = Vec::with_capacity(Self::_capacity(size));
+        let mut level_offsets = Vec::new();
+
+        level_offsets.push(0);
+        for leaf_data in items {
+            nodes.push(_sha3::<D>(leaf_data));
+        }
+
+        let mut level_start = 0;
+        let mut level_len = size;
+        while level_len > 1 {
+            level_offsets.push(nodes.len());
+
+            let mut i = 0;
+            while i < level_len {
+                let left = nodes[level_start + i].clone();
+                let right = if i + 1 < level_len {
+                    nodes[level_start + i + 1].clone()
+                } else {
+                    left.clone()
+                };
+                nodes.push(_sha3_leaves::<D>(&left, &right));
+                i += 2;
+            }
+
+            level_start = level_offsets[level_offsets.len() - 1];
+            level_len = Self::_next_level_len(level_len);
+        }
 
         Self {
-            root: *root,
+            nodes: nodes,
+            level_offsets: level_offsets,
             size: size,
+            _digest: PhantomData,
         }
     }
 
-    fn _build_up(mut nodes: Vec<Box<Node>>) -> Vec<Box<Node>> {
-        let node_count = nodes.len();
-        if node_count == 1 { return nodes }
-
-        if node_count % 2 == 1 {
-            let duplicate = nodes[node_count - 1].clone();
-            nodes.push(duplicate);
-        }
+    fn _next_level_len(len: usize) -> usize {
+        if len <= 1 { 0 } else { (len + 1) / 2 }
+    }
 
-        let mut parents = Vec::new();
-        let mut i = 0;
-        while i < nodes.len() {
-            let left_child = nodes[i].clone();
-            let right_child = nodes[i + 1].clone();
-            let combined_hash = _sha3_leaves(&left_child.hash, &right_child.hash);
-            let parent_node = Box::new(Node::new_internal(combined_hash, left_child, right_child));
-            parents.push(parent_node);
-            i += 2;
+    fn _capacity(size: usize) -> usize {
+        let mut total = size;
+        let mut len = size;
+        while len > 1 {
+            let next = Self::_next_level_len(len);
+            total += next;
+            len = next;
         }
+        total
+    }
 
-        return Self::_build_up(parents);
+    fn _level_len(&self, level: usize) -> usize {
+        let start = self.level_offsets[level];
+        let end = if level + 1 < self.level_offsets.len() {
+            self.level_offsets[level + 1]
+        } else {
+            self.nodes.len()
+        };
+        end - start
     }
 
     fn get_root_hash(&self) -> &[u8] {
-        &self.root.hash
+        &self.nodes[self.nodes.len() - 1]
     }
 
     fn get_proof(&self, index: usize) -> Option<Vec<Vec<u8>>> {
         if index >= self.size { return None }
 
-        let level_size = 2f64.powf((self.size as f64).log2().ceil()) as usize;
-        let mut position = level_size + index;
-        let mut directions = Vec::new();
+        let mut path = Vec::new();
+        let mut position = index;
+
+        for level in 0 .. self.level_offsets.len() - 1 {
+            let start = self.level_offsets[level];
+            let last = self._level_len(level) - 1;
+            let sibling = if (position ^ 1) > last { last } else { position ^ 1 };
+            path.push(self.nodes[start + sibling].clone());
+            position /= 2;
+        }
+
+        Some(path)
+    }
 
-        while position >= 2 {
-            let direction = position % 2;
-            position = position / 2;
-            directions.push(direction);
+    fn get_batch_proof(&self, indices: &[usize]) -> Option<Vec<Vec<u8>>> {
+        let mut known = Vec::new();
+        for &index in indices {
+            if index >= self.size { return None }
+            known.push(index);
         }
+        known.sort();
+        known.dedup();
+        if known.is_empty() { return None }
+
+        let mut proof = Vec::new();
+
+        for level in 0 .. self.level_offsets.len() - 1 {
+            let start = self.level_offsets[level];
+            let last = self._level_len(level) - 1;
+
+            let mut next_known = Vec::new();
+            let mut i = 0;
+            while i < known.len() {
+                let position = known[i];
+                let sibling = if (position ^ 1) > last { last } else { position ^ 1 };
+
+                if i + 1 < known.len() && known[i + 1] == sibling {
+                    // Both children are known, so the parent is derivable without a hint.
+                    i += 2;
+                } else if sibling == position {
+                    // Odd tail node paired with itself; nothing to emit.
+                    i += 1;
+                } else {
+                    proof.push(self.nodes[start + sibling].clone());
+                    i += 1;
+                }
+
+                next_known.push(position / 2);
+            }
 
-        for i in 0 .. directions.len() / 2 {
-            let opposite = directions.len() - i - 1;
-            let tmp = directions[i];
-            directions[i] = directions[opposite];
-            directions[opposite] = tmp;
+            next_known.dedup();
+            known = next_known;
         }
-        println!("Directions: {:?}", directions);
 
-        let mut path = Vec::new();
-        let mut node = &self.root;
-
-        for direction in directions {
-            if direction == 1 {
-                let mut hash = node.left.as_ref().unwrap().hash.to_owned();
-                hash.push('l' as u8);
-                path.push(hash);
-                node = &node.right.as_ref().unwrap();
+        Some(proof)
+    }
+
+    fn update_leaf(&mut self, index: usize, new_data: &[u8]) {
+        if index >= self.size { return }
+
+        let mut position = index;
+        self.nodes[position] = _sha3::<D>(new_data);
+
+        for level in 0 .. self.level_offsets.len() - 1 {
+            let start = self.level_offsets[level];
+            let last = self._level_len(level) - 1;
+            let sibling = if (position ^ 1) > last { last } else { position ^ 1 };
+
+            let current = self.nodes[start + position].clone();
+            let other = self.nodes[start + sibling].clone();
+            let parent_hash = if position % 2 == 0 {
+                _sha3_leaves::<D>(&current, &other)
             } else {
-                let mut hash = node.right.as_ref().unwrap().hash.to_owned();
-                hash.push('r' as u8);
-                path.push(hash);
-                node = &node.left.as_ref().unwrap();
+                _sha3_leaves::<D>(&other, &current)
+            };
+
+            position /= 2;
+            let parent_start = self.level_offsets[level + 1];
+            self.nodes[parent_start + position] = parent_hash;
+        }
+    }
+}
+
+
+fn verify_proof<D: Digest>(root_hash: &[u8], leaf_data: &[u8], index: usize, proof: &[Vec<u8>]) -> bool {
+    let mut acc = _sha3::<D>(leaf_data);
+    let mut position = index;
+
+    for sibling in proof {
+        if position % 2 == 1 {
+            acc = _sha3_leaves::<D>(sibling, &acc);
+        } else {
+            acc = _sha3_leaves::<D>(&acc, sibling);
+        }
+        position /= 2;
+    }
+
+    acc == root_hash
+}
+
+
+fn verify_batch_proof<D: Digest>(root_hash: &[u8], size: usize, indices: &[usize], leaves: &[&[u8]], proof: &[Vec<u8>]) -> bool {
+    if indices.len() != leaves.len() || size == 0 { return false }
+
+    let mut known: Vec<(usize, Vec<u8>)> = Vec::new();
+    for (&index, &leaf_data) in indices.iter().zip(leaves) {
+        if index >= size { return false }
+        known.push((index, _sha3::<D>(leaf_data)));
+    }
+    known.sort_by(|a, b| a.0.cmp(&b.0));
+    known.dedup_by(|a, b| a.0 == b.0);
+
+    let mut proof_pos = 0;
+    let mut level_len = size;
+    while level_len > 1 {
+        let last = level_len - 1;
+
+        let mut next_known = Vec::new();
+        let mut i = 0;
+        while i < known.len() {
+            let position = known[i].0;
+            let sibling = if (position ^ 1) > last { last } else { position ^ 1 };
+
+            let parent = if i + 1 < known.len() && known[i + 1].0 == sibling {
+                // Both children present; lower index sits on the left.
+                let parent = _sha3_leaves::<D>(&known[i].1, &known[i + 1].1);
+                i += 2;
+                parent
+            } else if sibling == position {
+                let parent = _sha3_leaves::<D>(&known[i].1, &known[i].1);
+                i += 1;
+                parent
+            } else {
+                if proof_pos >= proof.len() { return false }
+                let sibling_hash = &proof[proof_pos];
+                proof_pos += 1;
+                let parent = if position % 2 == 0 {
+                    _sha3_leaves::<D>(&known[i].1, sibling_hash)
+                } else {
+                    _sha3_leaves::<D>(sibling_hash, &known[i].1)
+                };
+                i += 1;
+                parent
+            };
+
+            next_known.push((position / 2, parent));
+        }
+
+        next_known.dedup_by(|a, b| a.0 == b.0);
+        known = next_known;
+        level_len = MerkleTree::<D>::_next_level_len(level_len);
+    }
+
+    proof_pos == proof.len() && known.len() == 1 && known[0].1 == root_hash
+}
+
+
+const SPARSE_DEPTH: usize = 256;
+
+
+struct Node {
+    left: Vec<u8>,
+    right: Vec<u8>,
+}
+
+struct SparseProof {
+    siblings: Vec<Vec<u8>>,
+    bitmap: Vec<u8>,
+    leaf: Vec<u8>,
+}
+
+struct SparseMerkleTree<D: Digest> {
+    store: HashMap<Vec<u8>, Node>,
+    defaults: Vec<Vec<u8>>,
+    root: Vec<u8>,
+    _digest: PhantomData<D>,
+}
+
+
+fn _bit(path: &[u8], level: usize) -> u8 {
+    (path[level / 8] >> (7 - level % 8)) & 1
+}
+
+fn _sparse_defaults<D: Digest>() -> Vec<Vec<u8>> {
+    let hash_len = _sha3::<D>(&[]).len();
+    let mut defaults = Vec::with_capacity(SPARSE_DEPTH + 1);
+    defaults.push(vec![0u8; hash_len]);
+    for level in 1 ..= SPARSE_DEPTH {
+        let child = &defaults[level - 1];
+        defaults.push(_sha3_leaves::<D>(child, child));
+    }
+    defaults
+}
+
+
+impl<D: Digest> SparseMerkleTree<D> {
+    fn new() -> Self {
+        let defaults = _sparse_defaults::<D>();
+        let root = defaults[SPARSE_DEPTH].clone();
+
+        Self {
+            store: HashMap::new(),
+            defaults: defaults,
+            root: root,
+            _digest: PhantomData,
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
+        let path = _sha3::<D>(key);
+        let leaf = _sha3::<D>(value);
+
+        let mut siblings = Vec::with_capacity(SPARSE_DEPTH);
+        let mut current = self.root.clone();
+        for level in 0 .. SPARSE_DEPTH {
+            let default = self.defaults[SPARSE_DEPTH - 1 - level].clone();
+            let (left, right) = match self.store.get(&current) {
+                Some(node) => (node.left.clone(), node.right.clone()),
+                None => (default.clone(), default),
+            };
+            if _bit(&path, level) == 0 {
+                siblings.push(right);
+                current = left;
+            } else {
+                siblings.push(left);
+                current = right;
             }
         }
 
-        for i in 0 .. path.len() / 2 {
-            let opposite_index = path.len() - i - 1;
-            path.swap(i, opposite_index);
+        let mut hash = leaf;
+        for level in (0 .. SPARSE_DEPTH).rev() {
+            let sibling = siblings[level].clone();
+            let (left, right) = if _bit(&path, level) == 0 {
+                (hash.clone(), sibling)
+            } else {
+                (sibling, hash.clone())
+            };
+            let parent = _sha3_leaves::<D>(&left, &right);
+            self.store.insert(parent.clone(), Node { left: left, right: right });
+            hash = parent;
         }
 
-        Some(path)
+        self.root = hash;
+    }
+
+    fn get_root(&self) -> &[u8] {
+        &self.root
+    }
+
+    fn get_proof(&self, key: &[u8]) -> SparseProof {
+        let path = _sha3::<D>(key);
+
+        let mut siblings = Vec::new();
+        let mut bitmap = vec![0u8; (SPARSE_DEPTH + 7) / 8];
+        let mut current = self.root.clone();
+        for level in 0 .. SPARSE_DEPTH {
+            let default = self.defaults[SPARSE_DEPTH - 1 - level].clone();
+            let (left, right) = match self.store.get(&current) {
+                Some(node) => (node.left.clone(), node.right.clone()),
+                None => (default.clone(), default.clone()),
+            };
+            let (sibling, next) = if _bit(&path, level) == 0 {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            if sibling != default {
+                bitmap[level / 8] |= 1 << (7 - level % 8);
+                siblings.push(sibling);
+            }
+            current = next;
+        }
+
+        SparseProof { siblings: siblings, bitmap: bitmap, leaf: current }
+    }
+}
+
+
+fn verify_sparse_proof<D: Digest>(root_hash: &[u8], key: &[u8], leaf: &[u8], proof: &SparseProof) -> bool {
+    let defaults = _sparse_defaults::<D>();
+    let path = _sha3::<D>(key);
+
+    let mut hash = leaf.to_vec();
+    let mut sibling_index = proof.siblings.len();
+    for level in (0 .. SPARSE_DEPTH).rev() {
+        let present = (proof.bitmap[level / 8] >> (7 - level % 8)) & 1 == 1;
+        let sibling = if present {
+            if sibling_index == 0 { return false }
+            sibling_index -= 1;
+            proof.siblings[sibling_index].clone()
+        } else {
+            defaults[SPARSE_DEPTH - 1 - level].clone()
+        };
+        let (left, right) = if _bit(&path, level) == 0 {
+            (hash.clone(), sibling)
+        } else {
+            (sibling, hash.clone())
+        };
+        hash = _sha3_leaves::<D>(&left, &right);
     }
+
+    sibling_index == 0 && hash == root_hash
 }
 
 
@@ -155,12 +428,12 @@ impl MerkleTree {
 mod tests {
     use sha3::Sha3_256;
 
-    use super::{MerkleTree, _sha3};
+    use super::{Sha3MerkleTree, SparseMerkleTree, _sha3, _sparse_defaults, verify_proof, verify_batch_proof, verify_sparse_proof};
 
 
     #[test]
     fn test_hash() {
-        assert_eq!(&_sha3(&vec![1,2,3]), &b"\xfd\x17\x80\xa6\xfc\x9e\xe0\xda\xb2l\xebK9A\xab\x03\xe6l\xcd\x97\r\x1d\xb9\x16\x12\xc6m\xf4Q[\n\n");
+        assert_eq!(&_sha3::<Sha3_256>(&vec![1,2,3]), &b"\x33\xba\xd5\x43\x08\x99\xed\x6f\x8b\xea\xf3\xe7\x32\xb2\xa2\xca\xd1\xd4\x0b\x7c\x9d\xe0\xcf\xcd\xc7\xe0\xbc\x07\x56\x80\x3a\x10");
     }
 
 
@@ -169,21 +442,21 @@ mod tests {
         let items: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![7,8,9]];
         let input = items.iter().map(|x| x.as_ref()).collect();
 
-        let merkle_tree = MerkleTree::build(input);
+        let merkle_tree = Sha3MerkleTree::build(input);
 
         assert_eq!(merkle_tree.size, 3);
-        assert_eq!(&merkle_tree.get_root_hash(), &b"\xbe\xa3\xfd\xa3\xa0\xb8=%\xef\xf3\xd4\x1cj\xa2\xd6=\x03I,\xcc0\xda\x1dg\x8a\x08o\x81g%1d");
+        assert_eq!(&merkle_tree.get_root_hash(), &b"\x78\x14\x1d\x36\xff\x98\x80\x7e\x36\x71\xe8\xcc\x8a\xfd\x1b\x8b\x13\xca\xce\x09\x7d\x8d\xcd\x3b\x00\x5c\xdf\x03\xa3\x1e\x8f\x16");
     }
 
     #[test]
     fn test_proof() {
         let items: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![7,8,9]];
         let input = items.iter().map(|x| x.as_ref()).collect();
-        let path_item_1: Vec<u8> = b"\xfd\x17\x80\xa6\xfc\x9e\xe0\xda\xb2l\xebK9A\xab\x03\xe6l\xcd\x97\r\x1d\xb9\x16\x12\xc6m\xf4Q[\n\nl".to_vec();
-        let path_item_2: Vec<u8> = b")\xf0\xb7]\x17K\xd38D.z\xca|X{0a\x8a\xe6\xa7\x03\x1e\xbeT\xb8:\xd1&\x8faK\xa2r".to_vec();
+        let path_item_1: Vec<u8> = b"\x33\xba\xd5\x43\x08\x99\xed\x6f\x8b\xea\xf3\xe7\x32\xb2\xa2\xca\xd1\xd4\x0b\x7c\x9d\xe0\xcf\xcd\xc7\xe0\xbc\x07\x56\x80\x3a\x10".to_vec();
+        let path_item_2: Vec<u8> = b"\xc9\x39\xdd\x2d\x16\xc3\x84\x95\x39\x75\x46\xc6\xb6\xde\x0d\x67\x87\x06\xba\xa1\xf4\xe3\x50\x99\xbe\x89\x22\xb0\xe6\xb8\x7c\x73".to_vec();
         let expected_proof = vec![path_item_1, path_item_2];
 
-        let merkle_tree = MerkleTree::build(input);
+        let merkle_tree = Sha3MerkleTree::build(input);
         let proof = merkle_tree.get_proof(1).unwrap();
 
         for (i, expected_hash) in expected_proof.iter().enumerate() {
@@ -192,4 +465,75 @@ mod tests {
             assert_eq!(actual_hash, expected_hash);
         }
     }
+
+    #[test]
+    fn test_verify_proof() {
+        let items: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![7,8,9]];
+        let input = items.iter().map(|x| x.as_ref()).collect();
+
+        let merkle_tree = Sha3MerkleTree::build(input);
+        let root_hash = merkle_tree.get_root_hash().to_vec();
+        let proof = merkle_tree.get_proof(1).unwrap();
+
+        assert!(verify_proof::<Sha3_256>(&root_hash, &items[1], 1, &proof));
+        assert!(!verify_proof::<Sha3_256>(&root_hash, &items[0], 1, &proof));
+    }
+
+    #[test]
+    fn test_batch_proof() {
+        let items: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![7,8,9], vec![10,11,12], vec![13,14,15]];
+        let input = items.iter().map(|x| x.as_ref()).collect();
+
+        let merkle_tree = Sha3MerkleTree::build(input);
+        let root_hash = merkle_tree.get_root_hash().to_vec();
+
+        let indices = vec![0, 1, 4];
+        let proof = merkle_tree.get_batch_proof(&indices).unwrap();
+        let leaves: Vec<&[u8]> = indices.iter().map(|&i| items[i].as_ref()).collect();
+
+        assert!(verify_batch_proof::<Sha3_256>(&root_hash, items.len(), &indices, &leaves, &proof));
+
+        let wrong_leaves: Vec<&[u8]> = vec![items[1].as_ref(), items[1].as_ref(), items[4].as_ref()];
+        assert!(!verify_batch_proof::<Sha3_256>(&root_hash, items.len(), &indices, &wrong_leaves, &proof));
+    }
+
+    #[test]
+    fn test_update_leaf() {
+        let original: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![7,8,9], vec![10,11,12]];
+        let updated: Vec<Vec<u8>> = vec![vec![1,2,3], vec![4,5,6], vec![13,14,15], vec![10,11,12]];
+
+        let mut merkle_tree = Sha3MerkleTree::build(original.iter().map(|x| x.as_ref()).collect());
+        merkle_tree.update_leaf(2, &[13,14,15]);
+
+        let rebuilt = Sha3MerkleTree::build(updated.iter().map(|x| x.as_ref()).collect());
+
+        assert_eq!(merkle_tree.get_root_hash(), rebuilt.get_root_hash());
+    }
+
+    #[test]
+    fn test_sparse_membership() {
+        let mut tree: SparseMerkleTree<Sha3_256> = SparseMerkleTree::new();
+        tree.insert(b"foo", &[1,2,3]);
+        tree.insert(b"bar", &[4,5,6]);
+
+        let root = tree.get_root().to_vec();
+        let proof = tree.get_proof(b"foo");
+        let leaf = _sha3::<Sha3_256>(&[1,2,3]);
+
+        assert_eq!(proof.leaf, leaf);
+        assert!(verify_sparse_proof::<Sha3_256>(&root, b"foo", &leaf, &proof));
+    }
+
+    #[test]
+    fn test_sparse_non_membership() {
+        let mut tree: SparseMerkleTree<Sha3_256> = SparseMerkleTree::new();
+        tree.insert(b"foo", &[1,2,3]);
+
+        let root = tree.get_root().to_vec();
+        let proof = tree.get_proof(b"missing");
+        let empty = _sparse_defaults::<Sha3_256>()[0].clone();
+
+        assert_eq!(proof.leaf, empty);
+        assert!(verify_sparse_proof::<Sha3_256>(&root, b"missing", &empty, &proof));
+    }
 }
\ No newline at end of file